@@ -1,9 +1,57 @@
-use rust_htslib::{bam, bam::record::{Record, Aux}, bam::Read};
+use rust_htslib::{bam, bam::record::{Cigar, CigarString, Record, Aux}, bam::Read};
 use rustc_hash::FxHashMap;
-use anyhow::{Context, Result};
+use anyhow::{anyhow, Context, Result};
+use flate2::write::GzEncoder;
+use flate2::Compression;
+use std::borrow::Cow;
+use std::collections::HashSet;
+use std::fs::File;
+use std::io::{BufWriter, Write};
 use std::path::PathBuf;
+use std::str::FromStr;
 use log::info;
 
+/// Output alignment format for `process_bam_file`, mirroring the formats
+/// rust-htslib's `bam::Writer` can emit.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum OutputFormat {
+    Bam,
+    Cram,
+    Sam,
+}
+
+impl OutputFormat {
+    /// File extension to use for the converted output file.
+    pub fn extension(&self) -> &'static str {
+        match self {
+            OutputFormat::Bam => "bam",
+            OutputFormat::Cram => "cram",
+            OutputFormat::Sam => "sam",
+        }
+    }
+
+    pub fn htslib_format(&self) -> bam::Format {
+        match self {
+            OutputFormat::Bam => bam::Format::Bam,
+            OutputFormat::Cram => bam::Format::Cram,
+            OutputFormat::Sam => bam::Format::Sam,
+        }
+    }
+}
+
+impl FromStr for OutputFormat {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s.to_lowercase().as_str() {
+            "bam" => Ok(OutputFormat::Bam),
+            "cram" => Ok(OutputFormat::Cram),
+            "sam" => Ok(OutputFormat::Sam),
+            other => Err(anyhow!("unknown output format '{other}', expected bam, cram or sam")),
+        }
+    }
+}
+
 
 #[derive(Clone, Debug)]
 pub enum TagValue {
@@ -62,6 +110,191 @@ impl TagValue {
             TagValue::UInt16Array(v) => Aux::ArrayU16(v),
         }
     }
+
+    /// Reverse a nanopore `mv` move-table array to match a reverse-complemented
+    /// read. The first element is the move stride, not a per-sample flag, so it
+    /// stays in place while the rest of the table reverses.
+    pub fn reverse_move_table(&self) -> TagValue {
+        match self {
+            TagValue::Int8Array(v) => {
+                let mut v = v.clone();
+                if v.len() > 1 {
+                    v[1..].reverse();
+                }
+                TagValue::Int8Array(v)
+            }
+            TagValue::UInt8Array(v) => {
+                let mut v = v.clone();
+                if v.len() > 1 {
+                    v[1..].reverse();
+                }
+                TagValue::UInt8Array(v)
+            }
+            other => other.clone(),
+        }
+    }
+}
+
+/// Complement a single IUPAC nucleotide code, leaving ambiguity codes (`N`, ...) untouched.
+fn complement_base(base: u8) -> u8 {
+    match base.to_ascii_uppercase() {
+        b'A' => b'T',
+        b'C' => b'G',
+        b'G' => b'C',
+        b'T' => b'A',
+        _ => base,
+    }
+}
+
+/// Reverse-complement a raw nucleotide sequence.
+pub fn reverse_complement(seq: &[u8]) -> Vec<u8> {
+    seq.iter().rev().map(|&b| complement_base(b)).collect()
+}
+
+/// Number of joint modification codes encoded by an `MM` group's code field
+/// (the part of the header after the base letter and strand, e.g. `mh` or
+/// `21839`, with any trailing `?`/`.` skip-scheme flag already stripped).
+///
+/// A run of letter codes (e.g. `mh` = 5mC+5hmC called jointly) carries one code
+/// per letter. A ChEBI numeric code (e.g. `21839`) is always a single code, no
+/// matter how many digits long. Anything else - in particular any mix of
+/// digits and letters - isn't a code grouping this parser understands, so
+/// callers must treat `None` as "can't safely determine the per-call `ML`
+/// stride" rather than guessing.
+fn mm_code_count(codes: &[u8]) -> Option<usize> {
+    if codes.is_empty() {
+        None
+    } else if codes.iter().all(u8::is_ascii_digit) {
+        Some(1)
+    } else if codes.iter().all(u8::is_ascii_alphabetic) {
+        Some(codes.len())
+    } else {
+        None
+    }
+}
+
+/// Recompute `MM`/`ML` base-modification tags for a reverse-complemented read.
+///
+/// `MM` lists, per modification group, how many unmodified instances of a base to
+/// skip between calls while walking the sequence 5'->3'. `ML` holds probability
+/// bytes in the same group-then-positional order, but *call-major*: a group with
+/// multiple joint codes (e.g. `C+mh` for 5mC+5hmC called together) contributes
+/// one byte per code per call, all of a call's codes adjacent before the next
+/// call's. Both tags are anchored to the literal bases in SEQ, so flipping the
+/// sequence means the base each group matches on complements, and the calls
+/// within a group - along with each call's whole `ML` chunk - reverse order.
+fn flip_modification_tags(mm: &[u8], ml: &[u8], original_seq: &[u8]) -> Result<(Vec<u8>, Vec<u8>)> {
+    let seq_len = original_seq.len();
+    let flipped_seq = reverse_complement(original_seq);
+    let mut new_mm = Vec::new();
+    let mut new_ml = Vec::with_capacity(ml.len());
+    let mut ml_cursor = 0usize;
+
+    for group in mm.split(|&b| b == b';').filter(|g| !g.is_empty()) {
+        let mut fields = group.split(|&b| b == b',');
+        let header = match fields.next() {
+            Some(h) if !h.is_empty() => h,
+            _ => continue,
+        };
+        let deltas: Vec<u32> = fields
+            .filter(|f| !f.is_empty())
+            .filter_map(|f| std::str::from_utf8(f).ok()?.parse().ok())
+            .collect();
+
+        let base = header[0];
+        let flipped_base = complement_base(base);
+
+        // header is `<base><strand><codes...><?|.>?`; a well-formed group
+        // needs at least a strand char and one code, so anything shorter
+        // can't be sliced into codes below - fail loudly instead of panicking.
+        if header.len() < 3 {
+            return Err(anyhow!(
+                "MM group '{}' is too short to contain a strand and modification code; refusing to guess its ML stride",
+                String::from_utf8_lossy(header)
+            ));
+        }
+
+        // Strip the optional trailing skip-scheme flag before counting
+        // modification codes.
+        let codes_end = match header.last() {
+            Some(b'?') | Some(b'.') => header.len() - 1,
+            _ => header.len(),
+        };
+        let codes = &header[2..codes_end];
+        let n_codes = mm_code_count(codes).with_context(|| format!(
+            "MM group '{}' has an unsupported modification code grouping; refusing to guess its ML stride",
+            String::from_utf8_lossy(header)
+        ))?;
+
+        // Positions (0-indexed, 5'->3') of every occurrence of `base` in the original sequence.
+        let base_positions: Vec<usize> = original_seq
+            .iter()
+            .enumerate()
+            .filter(|&(_, &b)| b.to_ascii_uppercase() == base.to_ascii_uppercase())
+            .map(|(i, _)| i)
+            .collect();
+
+        // Walk the deltas to find which of those positions are actually called.
+        let mut called = Vec::with_capacity(deltas.len());
+        let mut idx = 0usize;
+        for &delta in &deltas {
+            idx += delta as usize;
+            if idx >= base_positions.len() {
+                break;
+            }
+            called.push(base_positions[idx]);
+            idx += 1;
+        }
+
+        // Mirror those calls into the flipped sequence and re-derive skip counts
+        // against every occurrence of the complemented base there.
+        let called_flipped: HashSet<usize> = called.iter().map(|&p| seq_len - 1 - p).collect();
+        let flipped_base_positions: Vec<usize> = flipped_seq
+            .iter()
+            .enumerate()
+            .filter(|&(_, &b)| b.to_ascii_uppercase() == flipped_base.to_ascii_uppercase())
+            .map(|(i, _)| i)
+            .collect();
+
+        let mut new_deltas = Vec::with_capacity(called.len());
+        let mut since_last = 0u32;
+        for pos in flipped_base_positions {
+            if called_flipped.contains(&pos) {
+                new_deltas.push(since_last);
+                since_last = 0;
+            } else {
+                since_last += 1;
+            }
+        }
+
+        new_mm.push(flipped_base);
+        new_mm.extend_from_slice(&header[1..]);
+        for d in &new_deltas {
+            new_mm.push(b',');
+            new_mm.extend_from_slice(d.to_string().as_bytes());
+        }
+        new_mm.push(b';');
+
+        // ML packs `n_codes` bytes per call, call-major. Reverse whole
+        // call-sized chunks - not individual bytes - so a joint call's codes
+        // stay adjacent and in header order instead of getting interleaved.
+        let n_call_bytes = called.len() * n_codes;
+        if !ml.is_empty() {
+            let end = ml_cursor + n_call_bytes;
+            if end > ml.len() {
+                return Err(anyhow!(
+                    "ML array ({} bytes) is too short for MM group '{}' ({} call(s) x {} code(s) starting at byte {})",
+                    ml.len(), String::from_utf8_lossy(header), called.len(), n_codes, ml_cursor
+                ));
+            }
+            for chunk in ml[ml_cursor..end].chunks(n_codes).rev() {
+                new_ml.extend_from_slice(chunk);
+            }
+        }
+        ml_cursor += n_call_bytes;
+    }
+
+    Ok((new_mm, new_ml))
 }
 
 #[derive(Clone)]
@@ -81,6 +314,48 @@ impl UnalignedRead {
             .find(|(t, _)| t == tag_name)
             .map(|(_, v)| v)
     }
+
+    /// Re-orient this unaligned read to match a reverse-strand alignment, where
+    /// the aligner stores the sequence reverse-complemented and qualities
+    /// reversed relative to the basecaller's original read. `MM`/`ML` and `mv`
+    /// are positional, so they're recomputed/reversed rather than copied as-is.
+    pub fn reverse_complemented(&self) -> Result<UnalignedRead> {
+        let sequence = reverse_complement(&self.sequence);
+        let mut qualities = self.qualities.clone();
+        qualities.reverse();
+
+        let flipped_mm_ml = match (self.get_tag_value(b"MM"), self.get_tag_value(b"ML")) {
+            (Some(TagValue::String(mm)), Some(TagValue::UInt8Array(ml))) => {
+                Some(flip_modification_tags(mm, ml, &self.sequence)?)
+            }
+            (Some(TagValue::String(mm)), None) => {
+                Some(flip_modification_tags(mm, &[], &self.sequence)?)
+            }
+            _ => None,
+        };
+
+        let tags = self.tags.iter().map(|(name, value)| {
+            let flipped = match name.as_slice() {
+                b"MM" => flipped_mm_ml.as_ref().map(|(mm, _)| TagValue::String(mm.clone())),
+                b"ML" => flipped_mm_ml.as_ref().map(|(_, ml)| TagValue::UInt8Array(ml.clone())),
+                b"mv" => Some(value.reverse_move_table()),
+                _ => None,
+            };
+            (name.clone(), flipped.unwrap_or_else(|| value.clone()))
+        }).collect();
+
+        Ok(UnalignedRead { sequence, qualities, tags })
+    }
+}
+
+/// Re-orient `unaligned` to match its alignment's strand, borrowing it
+/// unchanged when no flip is needed instead of cloning for every read.
+fn oriented_for_strand(unaligned: &UnalignedRead, is_reverse: bool) -> Result<Cow<'_, UnalignedRead>> {
+    if is_reverse {
+        Ok(Cow::Owned(unaligned.reverse_complemented()?))
+    } else {
+        Ok(Cow::Borrowed(unaligned))
+    }
 }
 
 #[derive(Default)]
@@ -98,9 +373,12 @@ impl Stats {
 
 
 /// Create an index of reads from the unaligned BAM file
-pub fn create_read_index(path: &PathBuf) -> Result<FxHashMap<Vec<u8>, UnalignedRead>> {
+pub fn create_read_index(path: &PathBuf, threads: usize) -> Result<FxHashMap<Vec<u8>, UnalignedRead>> {
     info!("Creating index from unaligned BAM file...");
     let mut bam = bam::Reader::from_path(path)?;
+    if threads > 0 {
+        bam.set_threads(threads)?;
+    }
     let mut index = FxHashMap::default();
     let mut buffer = Record::new();
 
@@ -129,20 +407,136 @@ pub fn create_read_index(path: &PathBuf) -> Result<FxHashMap<Vec<u8>, UnalignedR
     Ok(index)
 }
 
-/// Convert CIGAR string from hard clips to soft clips
-fn convert_cigar(cigar: &[u32]) -> Vec<u32> {
-    cigar.iter()
-        .map(|&op| {
-            let op_type = op >> 4;
-            let op_len = op & 0xf;
-            // If hard clip (5), convert to soft clip (4)
-            if op_type == 5 {
-                (4 << 4) | op_len
-            } else {
-                op
-            }
-        })
-        .collect()
+/// Convert a CIGAR's hard clips to soft clips
+fn convert_cigar(cigar: &[Cigar]) -> CigarString {
+    CigarString(
+        cigar.iter()
+            .map(|op| match op {
+                Cigar::HardClip(len) => Cigar::SoftClip(*len),
+                other => other.clone(),
+            })
+            .collect()
+    )
+}
+
+/// Build the soft-clip-restored, tag-enriched, strand-corrected record for one
+/// aligned read, along with whether hard clips were converted. Returns `None`
+/// if its original basecalled read isn't present in the unaligned index.
+fn restore_record(
+    buffer: &Record,
+    name: &[u8],
+    unaligned_index: &FxHashMap<Vec<u8>, UnalignedRead>,
+    transfer_tags: &[String],
+) -> Result<Option<(Record, bool)>> {
+    let unaligned = match unaligned_index.get(name) {
+        Some(unaligned) => unaligned,
+        None => return Ok(None),
+    };
+
+    // Aligned records on the minus strand store the sequence reverse-
+    // complemented and qualities reversed relative to the original basecaller
+    // read; re-orient before restoring anything.
+    let unaligned = oriented_for_strand(unaligned, buffer.is_reverse())?;
+    let unaligned = unaligned.as_ref();
+
+    let cigar = buffer.cigar().take();
+    let has_hard_clips = cigar.iter().any(|op| matches!(op, Cigar::HardClip(_)));
+    let cigar = if has_hard_clips { convert_cigar(&cigar) } else { cigar };
+
+    let mut new_record = Record::new();
+
+    // set() packs qname/cigar/seq/qual into the record correctly (4-bit-encoded
+    // sequence, proper CIGAR op packing) instead of hand-rolling the raw bytes.
+    new_record.set(name, Some(&cigar), &unaligned.sequence, &unaligned.qualities);
+
+    // Copy basic fields
+    new_record.set_pos(buffer.pos());
+    new_record.set_mapq(buffer.mapq());
+    new_record.set_flags(buffer.flags());
+
+    // Preserve mate/reference linkage so downstream tools see valid pairing
+    new_record.set_tid(buffer.tid());
+    new_record.set_mtid(buffer.mtid());
+    new_record.set_mpos(buffer.mpos());
+    new_record.set_insert_size(buffer.insert_size());
+
+    // Transfer original tags
+    for result in buffer.aux_iter() {
+        if let Ok((tag, value)) = result {
+            new_record.push_aux(tag, value)?;
+        }
+    }
+
+    // Add new tags from unaligned read
+    for tag_name in transfer_tags {
+        let tag_bytes = tag_name.as_bytes();
+        // Only transfer the tag if:
+        // 1. It's in our transfer list
+        // 2. The unaligned read has it
+        // 3. The aligned read doesn't already have it
+        if tag_bytes.len() == 2 &&
+        unaligned.has_tag(tag_bytes) &&
+        !buffer.aux(tag_bytes).is_ok() {
+         if let Some(value) = unaligned.get_tag_value(tag_bytes) {
+             new_record.push_aux(tag_bytes, value.to_aux())?;
+         }
+     }
+    }
+
+    Ok(Some((new_record, has_hard_clips)))
+}
+
+/// Output file name/writer shared by the streaming and region-restricted code paths.
+fn build_writer(
+    input_path: &PathBuf,
+    input_header: &bam::HeaderView,
+    output_dir: &PathBuf,
+    output_format: OutputFormat,
+    threads: usize,
+    reference: Option<&PathBuf>,
+) -> Result<bam::Writer> {
+    let output_name = format!(
+        "{}_converted.{}",
+        input_path
+            .file_stem()
+            .context("Invalid input filename")?
+            .to_str()
+            .context("Invalid UTF-8 in filename")?,
+        output_format.extension()
+    );
+    let output_path = output_dir.join(output_name);
+
+    let header = bam::Header::from_template(input_header);
+    let mut output = bam::Writer::from_path(&output_path, &header, output_format.htslib_format())?;
+    if threads > 0 {
+        output.set_threads(threads)?;
+    }
+    if let Some(reference) = reference {
+        output.set_reference(reference)?;
+    }
+    Ok(output)
+}
+
+/// Parse `--regions` specs of the form `contig:start-end` (1-based, inclusive)
+/// into 0-based half-open `(tid, start, end)` ranges, resolving contig names
+/// against the BAM header.
+fn parse_regions(specs: &[String], header: &bam::HeaderView) -> Result<Vec<(i32, i64, i64)>> {
+    specs.iter().map(|spec| {
+        let (contig, range) = spec.split_once(':')
+            .with_context(|| format!("region '{spec}' must be in 'contig:start-end' form"))?;
+        let (start, end) = range.split_once('-')
+            .with_context(|| format!("region '{spec}' must be in 'contig:start-end' form"))?;
+        let start: i64 = start.parse().with_context(|| format!("invalid region start in '{spec}'"))?;
+        let end: i64 = end.parse().with_context(|| format!("invalid region end in '{spec}'"))?;
+        let tid = header.tid(contig.as_bytes())
+            .with_context(|| format!("unknown contig '{contig}' in region '{spec}'"))?;
+        Ok((tid as i32, start - 1, end))
+    }).collect()
+}
+
+/// Whether a record spanning `[pos, end)` on `tid` overlaps any requested region.
+fn overlaps_any_region(regions: &[(i32, i64, i64)], tid: i32, pos: i64, end: i64) -> bool {
+    regions.iter().any(|&(r_tid, r_start, r_end)| r_tid == tid && pos < r_end && end > r_start)
 }
 
 /// Process a single BAM file
@@ -151,24 +545,32 @@ pub fn process_bam_file(
     unaligned_index: &FxHashMap<Vec<u8>, UnalignedRead>,
     output_dir: &PathBuf,
     transfer_tags: &[String],
+    threads: usize,
+    output_format: OutputFormat,
+    reference: Option<&PathBuf>,
+    regions: &[String],
+    regions_only: bool,
 ) -> Result<Stats> {
+    if regions_only && !regions.is_empty() {
+        return process_bam_file_region_only(
+            input_path, unaligned_index, output_dir, transfer_tags, threads, output_format, reference, regions,
+        );
+    }
+
     let mut stats = Stats::new();
     let mut input = bam::Reader::from_path(input_path)?;
-    
-    // Create output path
-    let output_name = input_path
-        .file_name()
-        .context("Invalid input filename")?
-        .to_str()
-        .context("Invalid UTF-8 in filename")?
-        .replace(".bam", "_converted.bam");
-    let output_path = output_dir.join(output_name);
-    
-    let header = bam::Header::from_template(input.header());
-    let mut output = bam::Writer::from_path(&output_path, &header, bam::Format::Bam)?;
-    
+    if threads > 0 {
+        input.set_threads(threads)?;
+    }
+    if let Some(reference) = reference {
+        input.set_reference(reference)?;
+    }
+
+    let parsed_regions = parse_regions(regions, input.header())?;
+    let mut output = build_writer(input_path, input.header(), output_dir, output_format, threads, reference)?;
+
     let mut buffer = Record::new();
-    
+
     while let Some(result) = input.read(&mut buffer) {
         result?;
         stats.reads_processed += 1;
@@ -177,64 +579,20 @@ pub fn process_bam_file(
             info!("Processed {} reads...", stats.reads_processed);
         }
 
+        if !parsed_regions.is_empty() {
+            let end = buffer.cigar().end_pos();
+            if !overlaps_any_region(&parsed_regions, buffer.tid(), buffer.pos(), end) {
+                output.write(&buffer)?;
+                continue;
+            }
+        }
+
         let name = buffer.qname().to_vec();
-        let has_hard_clips = buffer
-            .raw_cigar()
-            .iter()
-            .any(|&op| (op >> 4) == 5);  // 5 is BAM_CHARD_CLIP
-
-        match unaligned_index.get(&name) {
-            Some(unaligned) => {
-                let mut new_record = Record::new();
-                
-                // Copy basic fields
-                new_record.set_qname(&name);
-                new_record.set_pos(buffer.pos());
-                new_record.set_mapq(buffer.mapq());
-                new_record.set_flags(buffer.flags());
-                
-                // Convert CIGAR if needed and prepare record data
-                let cigar = if has_hard_clips {
+        match restore_record(&buffer, &name, unaligned_index, transfer_tags)? {
+            Some((new_record, modified)) => {
+                if modified {
                     stats.reads_modified += 1;
-                    convert_cigar(buffer.raw_cigar())
-                } else {
-                    buffer.raw_cigar().to_vec()
-                };
-
-                // Create the full BAM record data
-                let mut data = Vec::new();
-                data.extend_from_slice(&name);
-                data.push(0u8); // null terminator for name
-                data.extend(cigar.iter().flat_map(|x| x.to_le_bytes()));
-                data.extend(&unaligned.sequence);
-                data.extend(&unaligned.qualities);
-
-                // Set the complete record data
-                new_record.set_data(&data);
-
-                // Transfer original tags
-                for result in buffer.aux_iter() {
-                    if let Ok((tag, value)) = result {
-                        new_record.push_aux(tag, value)?;
-                    }
                 }
-
-                // Add new tags from unaligned read
-                for tag_name in transfer_tags {
-                    let tag_bytes = tag_name.as_bytes();
-                    // Only transfer the tag if:
-                    // 1. It's in our transfer list
-                    // 2. The unaligned read has it
-                    // 3. The aligned read doesn't already have it
-                    if tag_bytes.len() == 2 && 
-                    unaligned.has_tag(tag_bytes) && 
-                    !buffer.aux(tag_bytes).is_ok() {
-                     if let Some(value) = unaligned.get_tag_value(tag_bytes) {
-                         new_record.push_aux(tag_bytes, value.to_aux())?;
-                     }
-                 }
-             }
-
                 output.write(&new_record)?;
             }
             None => {
@@ -248,6 +606,313 @@ pub fn process_bam_file(
         "File stats: processed={}, modified={}, missing={}",
         stats.reads_processed, stats.reads_modified, stats.reads_missing
     );
-    
+
+    Ok(stats)
+}
+
+/// Region-only variant of [`process_bam_file`]: instead of streaming the whole
+/// file, seek straight to each requested region via the BAM/CRAM index and emit
+/// only the reads found there, omitting everything else.
+fn process_bam_file_region_only(
+    input_path: &PathBuf,
+    unaligned_index: &FxHashMap<Vec<u8>, UnalignedRead>,
+    output_dir: &PathBuf,
+    transfer_tags: &[String],
+    threads: usize,
+    output_format: OutputFormat,
+    reference: Option<&PathBuf>,
+    regions: &[String],
+) -> Result<Stats> {
+    let mut stats = Stats::new();
+    let mut input = bam::IndexedReader::from_path(input_path).with_context(|| format!(
+        "no BAM/CRAM index found for {} - region-restricted processing requires a .bai/.csi index next to the input file",
+        input_path.display()
+    ))?;
+    if threads > 0 {
+        input.set_threads(threads)?;
+    }
+    if let Some(reference) = reference {
+        input.set_reference(reference)?;
+    }
+
+    let parsed_regions = parse_regions(regions, input.header())?;
+    let mut output = build_writer(input_path, input.header(), output_dir, output_format, threads, reference)?;
+
+    let mut buffer = Record::new();
+    // Overlapping/duplicate --regions specs re-fetch the same record more than
+    // once; track which records this file has already emitted so they're only
+    // written once.
+    let mut emitted: HashSet<(Vec<u8>, u16, i64)> = HashSet::new();
+
+    for &(tid, start, end) in &parsed_regions {
+        input.fetch((tid, start, end))?;
+
+        while let Some(result) = input.read(&mut buffer) {
+            result?;
+
+            let name = buffer.qname().to_vec();
+            if !emitted.insert((name.clone(), buffer.flags(), buffer.pos())) {
+                continue;
+            }
+
+            stats.reads_processed += 1;
+
+            if stats.reads_processed % 100_000 == 0 {
+                info!("Processed {} reads...", stats.reads_processed);
+            }
+
+            match restore_record(&buffer, &name, unaligned_index, transfer_tags)? {
+                Some((new_record, modified)) => {
+                    if modified {
+                        stats.reads_modified += 1;
+                    }
+                    output.write(&new_record)?;
+                }
+                None => {
+                    stats.reads_missing += 1;
+                    output.write(&buffer)?;
+                }
+            }
+        }
+    }
+
+    info!(
+        "File stats: processed={}, modified={}, missing={}",
+        stats.reads_processed, stats.reads_modified, stats.reads_missing
+    );
+
+    Ok(stats)
+}
+
+/// Render a transferred tag as the SAM `TAG:TYPE:VALUE` text form (e.g. `ts:i:5`,
+/// `mv:B:c,10,0,1,1`) for inclusion on a FASTQ description line.
+fn tag_to_sam_text(tag: &[u8], value: &TagValue) -> String {
+    let tag = String::from_utf8_lossy(tag);
+    fn join_comma<T: ToString>(values: &[T]) -> String {
+        values.iter().map(ToString::to_string).collect::<Vec<_>>().join(",")
+    }
+    match value {
+        TagValue::Int8(v) => format!("{tag}:i:{v}"),
+        TagValue::UInt8(v) => format!("{tag}:i:{v}"),
+        TagValue::Int16(v) => format!("{tag}:i:{v}"),
+        TagValue::UInt16(v) => format!("{tag}:i:{v}"),
+        TagValue::Int32(v) => format!("{tag}:i:{v}"),
+        TagValue::UInt32(v) => format!("{tag}:i:{v}"),
+        TagValue::Float(v) => format!("{tag}:f:{v}"),
+        TagValue::String(v) => format!("{tag}:Z:{}", String::from_utf8_lossy(v)),
+        TagValue::IntArray(v) => format!("{tag}:B:i,{}", join_comma(v)),
+        TagValue::UIntArray(v) => format!("{tag}:B:I,{}", join_comma(v)),
+        TagValue::Int8Array(v) => format!("{tag}:B:c,{}", join_comma(v)),
+        TagValue::UInt8Array(v) => format!("{tag}:B:C,{}", join_comma(v)),
+        TagValue::Int16Array(v) => format!("{tag}:B:s,{}", join_comma(v)),
+        TagValue::UInt16Array(v) => format!("{tag}:B:S,{}", join_comma(v)),
+    }
+}
+
+/// Export soft-clip-restored, strand-corrected reads as FASTQ instead of a
+/// converted BAM/CRAM/SAM file - one FASTQ per input, optionally gzipped. This
+/// mirrors the BAM<->FASTQ round-tripping other rust-bio tooling supports, but
+/// without the detour through a converted BAM that would lose the CIGAR-only
+/// recovered bases.
+pub fn export_fastq_file(
+    input_path: &PathBuf,
+    unaligned_index: &FxHashMap<Vec<u8>, UnalignedRead>,
+    output_dir: &PathBuf,
+    transfer_tags: &[String],
+    threads: usize,
+    gzip: bool,
+) -> Result<Stats> {
+    let mut stats = Stats::new();
+    let mut input = bam::Reader::from_path(input_path)?;
+    if threads > 0 {
+        input.set_threads(threads)?;
+    }
+
+    let output_name = format!(
+        "{}_converted.{}",
+        input_path
+            .file_stem()
+            .context("Invalid input filename")?
+            .to_str()
+            .context("Invalid UTF-8 in filename")?,
+        if gzip { "fastq.gz" } else { "fastq" }
+    );
+    let output_path = output_dir.join(output_name);
+    let file = File::create(&output_path)?;
+    let mut writer: Box<dyn Write> = if gzip {
+        Box::new(BufWriter::new(GzEncoder::new(file, Compression::default())))
+    } else {
+        Box::new(BufWriter::new(file))
+    };
+
+    let mut buffer = Record::new();
+
+    while let Some(result) = input.read(&mut buffer) {
+        result?;
+        stats.reads_processed += 1;
+
+        if stats.reads_processed % 100_000 == 0 {
+            info!("Processed {} reads...", stats.reads_processed);
+        }
+
+        let name = buffer.qname().to_vec();
+        let unaligned = match unaligned_index.get(&name) {
+            Some(unaligned) => unaligned,
+            None => {
+                stats.reads_missing += 1;
+                continue;
+            }
+        };
+
+        // Aligned records on the minus strand store the sequence reverse-
+        // complemented and qualities reversed relative to the original
+        // basecaller read; re-orient before restoring anything.
+        let unaligned = oriented_for_strand(unaligned, buffer.is_reverse())?;
+        let unaligned = unaligned.as_ref();
+
+        let description: Vec<String> = transfer_tags.iter()
+            .filter_map(|tag_name| {
+                let tag_bytes = tag_name.as_bytes();
+                if tag_bytes.len() != 2 {
+                    return None;
+                }
+                unaligned.get_tag_value(tag_bytes).map(|value| tag_to_sam_text(tag_bytes, value))
+            })
+            .collect();
+
+        write!(writer, "@{}", String::from_utf8_lossy(&name))?;
+        if !description.is_empty() {
+            write!(writer, " {}", description.join(" "))?;
+        }
+        writeln!(writer)?;
+        writer.write_all(&unaligned.sequence)?;
+        writeln!(writer)?;
+        writer.write_all(b"+")?;
+        writeln!(writer)?;
+        // FASTQ stores qualities as ASCII Phred+33, while our buffer holds raw Phred values.
+        let quality_line: Vec<u8> = unaligned.qualities.iter().map(|&q| q + 33).collect();
+        writer.write_all(&quality_line)?;
+        writeln!(writer)?;
+    }
+
+    info!(
+        "File stats: processed={}, modified={}, missing={}",
+        stats.reads_processed, stats.reads_modified, stats.reads_missing
+    );
+
     Ok(stats)
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn flip_modification_tags_single_call_single_code() {
+        let seq = b"AACCGT";
+        let (mm, ml) = flip_modification_tags(b"C+m,0;", &[200], seq).unwrap();
+        assert_eq!(mm, b"G+m,1;");
+        assert_eq!(ml, vec![200]);
+    }
+
+    #[test]
+    fn flip_modification_tags_multi_call_single_code() {
+        let seq = b"AACCGT";
+        let (mm, ml) = flip_modification_tags(b"C+m,0,0;", &[100, 200], seq).unwrap();
+        assert_eq!(mm, b"G+m,0,0;");
+        // Call order reverses; each call's single ML byte travels with it.
+        assert_eq!(ml, vec![200, 100]);
+    }
+
+    #[test]
+    fn flip_modification_tags_joint_codes_keep_call_bytes_together() {
+        let seq = b"AACCGT";
+        // Two joint-called codes ('m' and 'h') per call: ML is call-major,
+        // i.e. [call0_m, call0_h, call1_m, call1_h, ...].
+        let (mm, ml) = flip_modification_tags(b"C+mh,0,0;", &[10, 20, 30, 40], seq).unwrap();
+        assert_eq!(mm, b"G+mh,0,0;");
+        // Calls reverse order, but each call's (m, h) pair must stay adjacent
+        // and in header order - a flat byte reversal would wrongly interleave them.
+        assert_eq!(ml, vec![30, 40, 10, 20]);
+    }
+
+    #[test]
+    fn flip_modification_tags_multiple_groups() {
+        let seq = b"AACCGT";
+        let (mm, ml) = flip_modification_tags(b"C+m,0;A+a,0;", &[200, 77], seq).unwrap();
+        assert_eq!(mm, b"G+m,1;T+a,1;");
+        assert_eq!(ml, vec![200, 77]);
+    }
+
+    #[test]
+    fn flip_modification_tags_rejects_ambiguous_code_grouping() {
+        let seq = b"AACCGT";
+        // A group mixing digits and letters has no well-defined per-call ML
+        // stride; this must fail loudly instead of silently mis-slicing ML.
+        let err = flip_modification_tags(b"C+m21839,0;", &[10], seq);
+        assert!(err.is_err());
+    }
+
+    #[test]
+    fn flip_modification_tags_rejects_short_ml() {
+        let seq = b"AACCGT";
+        let err = flip_modification_tags(b"C+mh,0,0;", &[10, 20, 30], seq);
+        assert!(err.is_err());
+    }
+
+    #[test]
+    fn flip_modification_tags_rejects_truncated_header_instead_of_panicking() {
+        let seq = b"AACCGT";
+        // A corrupt/truncated MM group ("C;") has a single-byte header with no
+        // strand char or code - this must return an error, not index-panic.
+        let err = flip_modification_tags(b"C;", &[], seq);
+        assert!(err.is_err());
+    }
+
+    #[test]
+    fn tag_to_sam_text_renders_each_tag_value_variant() {
+        assert_eq!(tag_to_sam_text(b"ts", &TagValue::Int32(5)), "ts:i:5");
+        assert_eq!(tag_to_sam_text(b"ts", &TagValue::UInt8(5)), "ts:i:5");
+        assert_eq!(tag_to_sam_text(b"qs", &TagValue::Float(1.5)), "qs:f:1.5");
+        assert_eq!(
+            tag_to_sam_text(b"pi", &TagValue::String(b"abc".to_vec())),
+            "pi:Z:abc"
+        );
+        assert_eq!(
+            tag_to_sam_text(b"mv", &TagValue::Int8Array(vec![10, 0, 1, 1])),
+            "mv:B:c,10,0,1,1"
+        );
+        assert_eq!(
+            tag_to_sam_text(b"ns", &TagValue::UInt8Array(vec![1, 2, 3])),
+            "ns:B:C,1,2,3"
+        );
+    }
+
+    #[test]
+    fn fastq_quality_line_is_phred_plus_33() {
+        let qualities: Vec<u8> = vec![0, 30, 40];
+        let quality_line: Vec<u8> = qualities.iter().map(|&q| q + 33).collect();
+        assert_eq!(quality_line, vec![b'!', b'?', b'I']);
+    }
+
+    #[test]
+    fn output_format_from_str_accepts_known_names_case_insensitively() {
+        assert_eq!("bam".parse::<OutputFormat>().unwrap(), OutputFormat::Bam);
+        assert_eq!("BAM".parse::<OutputFormat>().unwrap(), OutputFormat::Bam);
+        assert_eq!("cram".parse::<OutputFormat>().unwrap(), OutputFormat::Cram);
+        assert_eq!("Sam".parse::<OutputFormat>().unwrap(), OutputFormat::Sam);
+        assert!("bcf".parse::<OutputFormat>().is_err());
+    }
+
+    #[test]
+    fn output_format_extension_and_htslib_format_match() {
+        for (format, extension, htslib_format) in [
+            (OutputFormat::Bam, "bam", bam::Format::Bam),
+            (OutputFormat::Cram, "cram", bam::Format::Cram),
+            (OutputFormat::Sam, "sam", bam::Format::Sam),
+        ] {
+            assert_eq!(format.extension(), extension);
+            assert_eq!(format.htslib_format(), htslib_format);
+        }
+    }
+}
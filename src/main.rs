@@ -5,7 +5,7 @@ use log::info;
 use rayon::prelude::*;
 
 // Import our library functionality
-use bambutler::{create_read_index, process_bam_file};
+use bambutler::{create_read_index, export_fastq_file, process_bam_file, OutputFormat};
 
 #[derive(Debug, StructOpt)]
 #[structopt(name = "bambutler", about = "Convert hard clips to soft clips and transfer tags")]
@@ -25,6 +25,48 @@ struct Opts {
     /// Tags to transfer (comma-separated list, e.g. "mv,ts,ns,pi")
     #[structopt(long, default_value = "", use_delimiter = true, value_delimiter = ',')]
     transfer_tags: Vec<String>,
+
+    /// htslib BGZF (de)compression thread budget, split evenly against the outer
+    /// rayon file-level parallelism: each file's reader and its writer each get
+    /// their own pool of this many threads divided by the number of files
+    /// processed at once (at least 1; 0 disables extra threads), so a single
+    /// large file still saturates multiple cores instead of sitting on one.
+    #[structopt(long, default_value = "1")]
+    threads: usize,
+
+    /// Output alignment format
+    #[structopt(long, default_value = "bam")]
+    output_format: OutputFormat,
+
+    /// Reference FASTA, required for CRAM output (and for reading CRAM input
+    /// that doesn't carry its own embedded reference)
+    #[structopt(long, parse(from_os_str))]
+    reference: Option<PathBuf>,
+
+    /// Restrict tag-transfer/restoration to these regions (e.g.
+    /// "chr1:1000-2000,chr2:5-10"); reads outside them are still streamed
+    /// through unchanged, so the whole file is read linearly and no index is
+    /// required. Combine with --regions-only to skip reads outside the given
+    /// regions entirely instead, which does require a .bai/.csi index next to
+    /// each aligned BAM, since that mode seeks straight to each region.
+    #[structopt(long, use_delimiter = true, value_delimiter = ',')]
+    regions: Vec<String>,
+
+    /// With --regions, omit reads outside the requested regions instead of
+    /// streaming them through unchanged. Seeks directly to each region via
+    /// the BAM/CRAM index instead of scanning the whole file.
+    #[structopt(long)]
+    regions_only: bool,
+
+    /// Emit restored reads as FASTQ (one file per input, qname as record ID,
+    /// --transfer-tags appended to the description line as TAG:TYPE:VALUE)
+    /// instead of a converted BAM/CRAM/SAM
+    #[structopt(long)]
+    fastq: bool,
+
+    /// Gzip the FASTQ output. Only used with --fastq
+    #[structopt(long)]
+    gzip_fastq: bool,
 }
 
 fn main() -> Result<()> {
@@ -35,12 +77,46 @@ fn main() -> Result<()> {
     std::fs::create_dir_all(&opts.output_dir)?;
 
     // Create index from unaligned BAM
-    let unaligned_index = create_read_index(&opts.unaligned_bam)?;
+    let unaligned_index = create_read_index(&opts.unaligned_bam, opts.threads)?;
+
+    // rayon already parallelizes across files, so only hand each htslib reader/writer
+    // a slice of the thread budget: enough files in flight at once that giving every
+    // one its own full thread pool would oversubscribe the machine.
+    let concurrent_files = opts.aligned_bams.len().max(1).min(rayon::current_num_threads());
+    // opts.threads == 0 is an explicit request to disable extra htslib
+    // threads entirely, matching the `threads > 0` gate used everywhere else;
+    // only floor to 1 when threads were actually requested.
+    let threads_per_file = if opts.threads == 0 {
+        0
+    } else {
+        (opts.threads / concurrent_files).max(1)
+    };
 
     // Process BAM files in parallel
     let results: Result<Vec<_>> = opts.aligned_bams
         .par_iter()
-        .map(|path| process_bam_file(path, &unaligned_index, &opts.output_dir, &opts.transfer_tags))
+        .map(|path| if opts.fastq {
+            export_fastq_file(
+                path,
+                &unaligned_index,
+                &opts.output_dir,
+                &opts.transfer_tags,
+                threads_per_file,
+                opts.gzip_fastq,
+            )
+        } else {
+            process_bam_file(
+                path,
+                &unaligned_index,
+                &opts.output_dir,
+                &opts.transfer_tags,
+                threads_per_file,
+                opts.output_format,
+                opts.reference.as_ref(),
+                &opts.regions,
+                opts.regions_only,
+            )
+        })
         .collect();
 
     // Aggregate statistics
@@ -1,7 +1,9 @@
 // tests/integration_test.rs
-use bambutler::{create_read_index, process_bam_file};
-use anyhow::Result;
+use bambutler::{create_read_index, process_bam_file, OutputFormat};
+use anyhow::{bail, Result};
 use rust_htslib::bam::{self, Read};
+use std::collections::HashSet;
+use std::fs;
 use tempfile::TempDir;
 use rust_htslib::bam::record::Aux;
 
@@ -11,7 +13,7 @@ use common::{count_cigar_ops, test_data_dir};
 #[test]
 fn test_create_read_index() -> Result<()> {
     let unaligned_path = test_data_dir().join("unaligned.bam");
-    let index = create_read_index(&unaligned_path)?;
+    let index = create_read_index(&unaligned_path, 1)?;
     
     // Create a Vec<u8> for the test read name
     let test_read_name = b"5bddecba-5f37-4b05-b3f3-170e77949d6f".to_vec();
@@ -33,7 +35,7 @@ fn test_process_bam_file() -> Result<()> {
     let aligned_path = test_dir.join("aligned_MD_sorted.bam");
     let unaligned_path = test_dir.join("unaligned.bam");
     
-    let unaligned_index = create_read_index(&unaligned_path)?;
+    let unaligned_index = create_read_index(&unaligned_path, 1)?;
 
     // Test mandatory tags (mv as B:c array and ts as integer)
     // plus an optional tag (pi)
@@ -42,12 +44,17 @@ fn test_process_bam_file() -> Result<()> {
         "ts".to_string(),  // timestamp (integer)
         "pi".to_string()   // optional tag
     ];
-    
+
     let stats = process_bam_file(
         &aligned_path,
         &unaligned_index,
         &temp_dir.path().to_path_buf(),
-        &transfer_tags
+        &transfer_tags,
+        1,
+        OutputFormat::Bam,
+        None,
+        &[],
+        false,
     )?;
     
     let output_path = temp_dir.path().join(
@@ -58,9 +65,12 @@ fn test_process_bam_file() -> Result<()> {
             .replace(".bam", "_converted.bam")
     );
     
+    let mut source_bam = bam::Reader::from_path(&aligned_path)?;
+    let mut source_record = bam::Record::new();
+
     let mut output_bam = bam::Reader::from_path(&output_path)?;
     let mut record = bam::Record::new();
-    
+
     while let Some(result) = output_bam.read(&mut record) {
         result?;
         assert_eq!(
@@ -68,10 +78,25 @@ fn test_process_bam_file() -> Result<()> {
             0,
             "Output BAM should not contain hard clips"
         );
-        
+
         assert!(!record.seq().as_bytes().is_empty(), "Record should have sequence");
         assert!(!record.qual().is_empty(), "Record should have quality scores");
-        
+
+        // Restoration must preserve mate/reference linkage as-is from the
+        // source alignment, or downstream tools see corrupted pairing.
+        match source_bam.read(&mut source_record) {
+            Some(result) => result?,
+            None => panic!("output BAM has more records than the source aligned BAM"),
+        }
+        assert_eq!(record.tid(), source_record.tid(), "tid should survive restoration");
+        assert_eq!(record.pos(), source_record.pos(), "pos should survive restoration");
+        assert_eq!(record.mtid(), source_record.mtid(), "mtid should survive restoration");
+        assert_eq!(record.mpos(), source_record.mpos(), "mpos should survive restoration");
+        assert_eq!(
+            record.insert_size(), source_record.insert_size(),
+            "insert_size should survive restoration"
+        );
+
         let qname = record.qname().to_vec();
         if let Some(unaligned) = unaligned_index.get(&qname) {
                        // Check mandatory tags with correct types
@@ -103,5 +128,202 @@ fn test_process_bam_file() -> Result<()> {
         "Modified reads should not exceed total reads"
     );
 
+    Ok(())
+}
+
+#[test]
+fn test_process_bam_file_regions_without_regions_only_streams_rest_unchanged() -> Result<()> {
+    let temp_dir = TempDir::new()?;
+    let test_dir = test_data_dir();
+
+    let aligned_path = test_dir.join("aligned_MD_sorted.bam");
+    let unaligned_path = test_dir.join("unaligned.bam");
+    let unaligned_index = create_read_index(&unaligned_path, 1)?;
+
+    // Build a region spec that covers only the first read in the file, so
+    // every other read is expected to stream straight through unmodified.
+    let mut probe = bam::Reader::from_path(&aligned_path)?;
+    let mut first = bam::Record::new();
+    match probe.read(&mut first) {
+        Some(result) => result?,
+        None => bail!("aligned BAM fixture has no reads"),
+    }
+    let contig = std::str::from_utf8(probe.header().tid2name(first.tid() as u32))?.to_string();
+    let region = format!("{contig}:{}-{}", first.pos() + 1, first.cigar().end_pos());
+
+    let transfer_tags = vec!["mv".to_string(), "ts".to_string()];
+    let stats = process_bam_file(
+        &aligned_path,
+        &unaligned_index,
+        &temp_dir.path().to_path_buf(),
+        &transfer_tags,
+        1,
+        OutputFormat::Bam,
+        None,
+        &[region],
+        false,
+    )?;
+
+    let output_path = temp_dir.path().join(
+        aligned_path.file_name()
+            .unwrap()
+            .to_str()
+            .unwrap()
+            .replace(".bam", "_converted.bam")
+    );
+
+    let mut output_bam = bam::Reader::from_path(&output_path)?;
+    let mut record = bam::Record::new();
+    let mut output_count = 0;
+    let mut saw_untouched_hard_clip = false;
+
+    while let Some(result) = output_bam.read(&mut record) {
+        result?;
+        output_count += 1;
+        if count_cigar_ops(&record, 5) > 0 {
+            saw_untouched_hard_clip = true;
+        }
+    }
+
+    // --regions alone must not drop anything - only --regions-only does that.
+    assert_eq!(
+        output_count, stats.reads_processed,
+        "regions without --regions-only must stream every read through"
+    );
+    assert!(
+        saw_untouched_hard_clip,
+        "reads outside the requested region should pass through unmodified, hard clips and all"
+    );
+
+    Ok(())
+}
+
+#[test]
+fn test_process_bam_file_region_only_omits_and_dedups() -> Result<()> {
+    let temp_dir = TempDir::new()?;
+    let test_dir = test_data_dir();
+    let unaligned_path = test_dir.join("unaligned.bam");
+    let unaligned_index = create_read_index(&unaligned_path, 1)?;
+
+    // process_bam_file_region_only requires a .bai/.csi index next to the
+    // input, so build one for a temp copy of the sorted fixture.
+    let aligned_path = temp_dir.path().join("aligned_MD_sorted.bam");
+    fs::copy(test_dir.join("aligned_MD_sorted.bam"), &aligned_path)?;
+    bam::index::build(&aligned_path, None, bam::index::Type::Bai, 1)?;
+
+    let mut probe = bam::Reader::from_path(&aligned_path)?;
+    let mut first = bam::Record::new();
+    match probe.read(&mut first) {
+        Some(result) => result?,
+        None => bail!("aligned BAM fixture has no reads"),
+    }
+    let contig = std::str::from_utf8(probe.header().tid2name(first.tid() as u32))?.to_string();
+    let start = first.pos() + 1;
+    let end = first.cigar().end_pos();
+    let mid = (start + end) / 2;
+
+    // Two overlapping region specs covering the same first read, to exercise
+    // both "out of region is omitted" and "overlap is not duplicated".
+    let regions = vec![
+        format!("{contig}:{start}-{end}"),
+        format!("{contig}:{mid}-{end}"),
+    ];
+
+    let output_dir = temp_dir.path().join("out");
+    fs::create_dir_all(&output_dir)?;
+    let transfer_tags = vec!["mv".to_string(), "ts".to_string()];
+
+    let stats = process_bam_file(
+        &aligned_path,
+        &unaligned_index,
+        &output_dir,
+        &transfer_tags,
+        1,
+        OutputFormat::Bam,
+        None,
+        &regions,
+        true,
+    )?;
+
+    let output_path = output_dir.join("aligned_MD_sorted_converted.bam");
+    let mut output_bam = bam::Reader::from_path(&output_path)?;
+    let mut record = bam::Record::new();
+    let mut seen = HashSet::new();
+    let mut output_count = 0;
+
+    while let Some(result) = output_bam.read(&mut record) {
+        result?;
+        output_count += 1;
+        let key = (record.qname().to_vec(), record.flags(), record.pos());
+        assert!(
+            seen.insert(key),
+            "a read overlapping two requested regions must appear exactly once"
+        );
+        let overlaps = record.tid() == first.tid()
+            && record.pos() < end
+            && record.cigar().end_pos() > (start - 1);
+        assert!(overlaps, "--regions-only must omit reads outside every requested region");
+    }
+
+    assert!(output_count > 0, "the overlapping read should still be emitted");
+    assert_eq!(
+        output_count, stats.reads_processed,
+        "reported reads_processed should match the deduplicated output count"
+    );
+
+    Ok(())
+}
+
+#[test]
+fn test_process_bam_file_sam_output() -> Result<()> {
+    let temp_dir = TempDir::new()?;
+    let test_dir = test_data_dir();
+
+    let aligned_path = test_dir.join("aligned_MD_sorted.bam");
+    let unaligned_path = test_dir.join("unaligned.bam");
+    let unaligned_index = create_read_index(&unaligned_path, 1)?;
+
+    let transfer_tags = vec!["mv".to_string(), "ts".to_string()];
+
+    let stats = process_bam_file(
+        &aligned_path,
+        &unaligned_index,
+        &temp_dir.path().to_path_buf(),
+        &transfer_tags,
+        1,
+        OutputFormat::Sam,
+        None,
+        &[],
+        false,
+    )?;
+
+    let output_path = temp_dir.path().join(
+        aligned_path.file_name()
+            .unwrap()
+            .to_str()
+            .unwrap()
+            .replace(".bam", "_converted.sam")
+    );
+    assert_eq!(
+        output_path.extension().and_then(|e| e.to_str()),
+        Some("sam"),
+        "SAM output should use a .sam extension"
+    );
+
+    let mut output_sam = bam::Reader::from_path(&output_path)?;
+    let mut record = bam::Record::new();
+    let mut seqs = Vec::new();
+
+    while let Some(result) = output_sam.read(&mut record) {
+        result?;
+        assert!(!record.seq().as_bytes().is_empty(), "Record should have sequence");
+        seqs.push(record.seq().as_bytes());
+    }
+
+    assert_eq!(
+        seqs.len(), stats.reads_processed,
+        "every processed read should round-trip through the SAM output"
+    );
+
     Ok(())
 }
\ No newline at end of file